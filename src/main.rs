@@ -1,3 +1,4 @@
+use dashmap::DashMap;
 use deadpool_postgres::{Manager, Pool};
 use futures_util::{
     future,
@@ -5,15 +6,28 @@ use futures_util::{
     stream::{self, StreamExt},
 };
 use serde::Deserialize;
-use slog_scope::{crit, error};
-use std::{convert::TryFrom, sync::Arc};
+use slog_scope::{crit, error, warn};
+use std::{
+    convert::TryFrom,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 use tgcd::{
-    raw::{tgcd_server, AddTags, GetMultipleTagsReq, GetMultipleTagsResp, Hash, SrcDest, Tags},
+    raw::{
+        tgcd_server, AddTags, EnqueueTaskReq, GetMultipleTagsReq, GetMultipleTagsResp, Hash,
+        SrcDest, TaskId, TaskStatusResp, Tags,
+    },
     Blake2bHash, HashError, Tag, TagError,
 };
 use thiserror::Error;
-use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::broadcast,
+};
 use tokio_postgres as postgres;
+use tokio_stream::{wrappers::BroadcastStream, Stream};
 use tonic::{transport::Server, Request, Response, Status};
 
 #[derive(Deserialize)]
@@ -25,6 +39,12 @@ struct Config {
 
     #[serde(default = "pool_size")]
     pool_size: usize,
+
+    #[serde(default = "metrics_port")]
+    metrics_port: u16,
+
+    #[serde(flatten)]
+    tls: tls::TlsConfig,
 }
 
 fn port() -> u16 {
@@ -35,6 +55,10 @@ fn pool_size() -> usize {
     16
 }
 
+fn metrics_port() -> u16 {
+    9090
+}
+
 #[derive(Clone)]
 struct Tgcd {
     inner: Arc<TgcdInner>,
@@ -44,13 +68,20 @@ mod embedded {
     refinery::embed_migrations!("sql");
 }
 
+mod metrics;
+mod retry;
+mod task;
+mod tls;
+
 impl Tgcd {
     async fn new(cfg: &Config) -> Result<Self, SetupError> {
         // TODO: remove unwrap
-        let postgres_config: postgres::config::Config = cfg.postgres_url.parse().unwrap();
+        let mut postgres_config: postgres::config::Config = cfg.postgres_url.parse().unwrap();
+        tls::apply_ssl_mode(&cfg.tls, &mut postgres_config);
+        let connector = tls::Connector::new(&cfg.tls)?;
 
         let (mut client, pg) = postgres_config
-            .connect(postgres::NoTls)
+            .connect(connector.clone())
             .await
             .map_err(SetupError::PostgresConnect)?;
         // after client is dropped the pg future also terminates
@@ -60,17 +91,176 @@ impl Tgcd {
             .run_async(&mut client)
             .await?;
 
-        let mngr = Manager::new(postgres_config, postgres::NoTls);
+        let watchers = Arc::new(DashMap::new());
+        let (task_wake_tx, task_wake_rx) = tokio::sync::mpsc::unbounded_channel();
+        spawn_notification_listener(
+            postgres_config.clone(),
+            connector.clone(),
+            Arc::clone(&watchers),
+            task_wake_tx,
+        )
+        .await?;
+
+        let mngr = Manager::new(postgres_config, connector);
         let pool = Pool::new(mngr, cfg.pool_size);
 
+        let metrics = Arc::new(metrics::Metrics::new().map_err(SetupError::Metrics)?);
+
         Ok(Self {
-            inner: Arc::new(TgcdInner { pool }),
+            inner: Arc::new(TgcdInner {
+                pool,
+                watchers,
+                metrics,
+                task_wake_rx: std::sync::Mutex::new(Some(task_wake_rx)),
+            }),
         })
     }
 }
 
 struct TgcdInner {
     pool: Pool,
+    watchers: Arc<DashMap<Blake2bHash, broadcast::Sender<()>>>,
+    metrics: Arc<metrics::Metrics>,
+    /// Taken by `run` once, to spawn the task-queue worker.
+    task_wake_rx: std::sync::Mutex<Option<tokio::sync::mpsc::UnboundedReceiver<()>>>,
+}
+
+const LISTENER_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const LISTENER_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Opens a dedicated (non-pooled) connection, issues `LISTEN tag_changed` on it
+/// and spawns a background task that wakes up `watch_tags` subscribers.
+///
+/// This has to stay off the deadpool `Pool`, since `LISTEN` is session state
+/// that must live on one connection for as long as we care about its
+/// notifications. Unlike the pool, there's no `retry::with_retry` to fall
+/// back on here, so the spawned task reconnects itself whenever the connection
+/// drops (with a doubling backoff from `LISTENER_INITIAL_BACKOFF` up to
+/// `LISTENER_MAX_BACKOFF`), instead of leaving `watch_tags` without updates
+/// and the task queue on its poll fallback for the rest of the process's life
+/// after one blip.
+async fn spawn_notification_listener(
+    postgres_config: postgres::config::Config,
+    connector: tls::Connector,
+    watchers: Arc<DashMap<Blake2bHash, broadcast::Sender<()>>>,
+    task_wake: tokio::sync::mpsc::UnboundedSender<()>,
+) -> Result<(), SetupError> {
+    let (client, connection) = connect_listener(&postgres_config, connector.clone())
+        .await
+        .map_err(SetupError::PostgresConnect)?;
+
+    let (notification_tx, notification_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::task::spawn(async move {
+        let mut current = Some((client, connection));
+        let mut backoff = LISTENER_INITIAL_BACKOFF;
+
+        loop {
+            let (client, mut connection) = match current.take() {
+                Some(conn) => conn,
+                None => match connect_listener(&postgres_config, connector.clone()).await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("Failed to reconnect notification listener"; slog::o!("error" => e.to_string()));
+                        tokio::time::sleep(backoff).await;
+                        backoff = std::cmp::min(backoff * 2, LISTENER_MAX_BACKOFF);
+                        continue;
+                    }
+                },
+            };
+            backoff = LISTENER_INITIAL_BACKOFF;
+
+            // keep `client` alive for as long as the connection runs: dropping it
+            // would close the socket the `LISTEN` is bound to.
+            let _client = client;
+            while let Some(message) = future::poll_fn(|cx| connection.poll_message(cx)).await {
+                match message {
+                    Ok(postgres::AsyncMessage::Notification(notification)) => {
+                        if notification_tx.send(notification).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Notification connection failed"; slog::o!("error" => e.to_string()));
+                        break;
+                    }
+                }
+            }
+
+            warn!("Notification listener connection lost, reconnecting");
+        }
+    });
+
+    tokio::task::spawn(delegate_notifications(notification_rx, watchers, task_wake));
+
+    Ok(())
+}
+
+/// Connects a fresh listener connection and issues the `LISTEN`s it needs.
+async fn connect_listener(
+    postgres_config: &postgres::config::Config,
+    connector: tls::Connector,
+) -> Result<
+    (
+        postgres::Client,
+        postgres::Connection<
+            postgres::Socket,
+            <tls::Connector as postgres::tls::MakeTlsConnect<postgres::Socket>>::Stream,
+        >,
+    ),
+    postgres::Error,
+> {
+    let (client, connection) = postgres_config.connect(connector).await?;
+    client
+        .batch_execute("LISTEN tag_changed; LISTEN task_queue")
+        .await?;
+    Ok((client, connection))
+}
+
+/// Demultiplexes the one listener connection's notifications: `tag_changed`
+/// wakes `watch_tags` subscribers (decoding the payload back into a
+/// `Blake2bHash`, and opportunistically dropping the `broadcast::Sender` if
+/// sending to it fails because the last subscriber is already gone); `task_queue`
+/// wakes the background-job worker. The primary cleanup for `watchers` entries
+/// is [`HashWatchStream`]'s `Drop` impl — this just catches entries that
+/// already lost their last subscriber before the next notification arrives.
+async fn delegate_notifications(
+    mut notifications: tokio::sync::mpsc::UnboundedReceiver<postgres::Notification>,
+    watchers: Arc<DashMap<Blake2bHash, broadcast::Sender<()>>>,
+    task_wake: tokio::sync::mpsc::UnboundedSender<()>,
+) {
+    while let Some(notification) = notifications.recv().await {
+        match notification.channel() {
+            "tag_changed" => {
+                let hash = match hex::decode(notification.payload()) {
+                    Ok(bytes) => match Blake2bHash::try_from(&*bytes) {
+                        Ok(hash) => hash,
+                        Err(e) => {
+                            warn!("Received tag_changed notification with invalid hash"; slog::o!("error" => e.to_string()));
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Received tag_changed notification with invalid payload"; slog::o!("error" => e.to_string()));
+                        continue;
+                    }
+                };
+
+                // the `get` guard must be gone before `remove`, which locks
+                // the same shard
+                let send_failed = watchers
+                    .get(&hash)
+                    .map_or(false, |sender| sender.send(()).is_err());
+                if send_failed {
+                    watchers.remove(&hash);
+                }
+            }
+            "task_queue" => {
+                let _ = task_wake.send(());
+            }
+            other => warn!("Received notification on unknown channel"; slog::o!("channel" => other.to_owned())),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -93,6 +283,15 @@ pub enum SetupError {
 
     #[error("Can't register signal: {0}")]
     Signal(std::io::Error),
+
+    #[error("Can't set up TLS: {0}")]
+    Tls(#[from] tls::TlsSetupError),
+
+    #[error("Can't set up metrics: {0}")]
+    Metrics(#[source] prometheus::Error),
+
+    #[error("Can't bind metrics server: {0}")]
+    MetricsBind(#[source] hyper::Error),
 }
 
 #[derive(Error, Debug)]
@@ -100,11 +299,17 @@ pub enum Error {
     #[error("Error from postgres: {0}")]
     Postgres(#[from] postgres::Error),
 
+    #[error("Error from connection pool: {0}")]
+    Pool(#[from] deadpool_postgres::PoolError),
+
     #[error("Invalid hash: {0}")]
     ArgHash(HashError),
 
     #[error("Invalid tag: {0}")]
     ArgTag(TagError),
+
+    #[error("Invalid task payload: {0}")]
+    InvalidTaskPayload(String),
 }
 
 impl From<Error> for Status {
@@ -114,14 +319,19 @@ impl From<Error> for Status {
                 error!("Db error"; slog::o!("error" => e.to_string()));
                 Status::new(tonic::Code::Unavailable, "db error")
             }
+            Error::Pool(e) => {
+                error!("Db pool error"; slog::o!("error" => e.to_string()));
+                Status::new(tonic::Code::Unavailable, "db error")
+            }
             Error::ArgHash(_) | Error::ArgTag(_) => {
                 Status::new(tonic::Code::InvalidArgument, "Received invalid argument")
             }
+            Error::InvalidTaskPayload(e) => Status::new(tonic::Code::InvalidArgument, e),
         }
     }
 }
 
-async fn get_tags(client: &postgres::Client, hash: &Blake2bHash) -> Result<Vec<String>, Error> {
+pub(crate) async fn get_tags(client: &postgres::Client, hash: &Blake2bHash) -> Result<Vec<String>, Error> {
     let stmnt = client
         .prepare(
             "
@@ -137,7 +347,7 @@ async fn get_tags(client: &postgres::Client, hash: &Blake2bHash) -> Result<Vec<S
     Ok(tags.into_iter().map(|row| row.get(0)).collect())
 }
 
-async fn get_or_insert_hash(
+pub(crate) async fn get_or_insert_hash(
     client: &postgres::Transaction<'_>,
     hash: &Blake2bHash,
 ) -> Result<i32, Error> {
@@ -165,119 +375,266 @@ async fn get_or_insert_hash(
     Ok(row.get(0))
 }
 
-async fn get_or_insert_tag(txn: &postgres::Transaction<'_>, tag: &str) -> Result<i32, Error> {
-    let stmnt = txn
-        .prepare(
-            "
-    WITH inserted AS (
-        INSERT INTO tag(name)
-        VALUES($1)
-        ON CONFLICT DO NOTHING
-        RETURNING id
-    )
-    SELECT * FROM inserted
+pub(crate) async fn add_tags_to_hash(
+    txn: &postgres::Transaction<'_>,
+    hash: &Blake2bHash,
+    tags: &[Tag],
+) -> Result<(), Error> {
+    let hash_id = get_or_insert_hash(&txn, &hash).await?;
 
-    UNION ALL
+    // Set-based instead of one `get_or_insert_tag` + one `INSERT INTO
+    // hash_tag` per tag: three statements regardless of how many tags are
+    // being added, instead of O(n) round-trips.
+    let tag_names = tags.iter().map(|tag: &Tag| -> &str { tag }).collect::<Vec<_>>();
 
-    SELECT id FROM tag
-    WHERE name = $1
+    let insert_tags = txn
+        .prepare("INSERT INTO tag(name) SELECT unnest($1::text[]) ON CONFLICT DO NOTHING")
+        .await?;
+    txn.execute(&insert_tags, &[&tag_names]).await?;
+
+    let insert_hash_tags = txn
+        .prepare(
+            "
+    INSERT INTO hash_tag(tag_id, hash_id)
+    SELECT t.id, $2
+    FROM tag t
+    WHERE t.name = ANY($1::text[])
+    ON CONFLICT DO NOTHING
     ",
         )
         .await?;
+    txn.execute(&insert_hash_tags, &[&tag_names, &hash_id])
+        .await?;
 
-    let row = txn.query_one(&stmnt, &[&tag]).await?;
+    Ok(())
+}
 
-    Ok(row.get(0))
+/// Wraps a per-hash change stream so that dropping it (the subscriber
+/// disconnecting, e.g. a `watch_tags` client going away) removes the
+/// `watchers` entry once it was the last subscriber. Without this, a hash
+/// that's watched once and never touched again keeps its `broadcast::Sender`
+/// around for the life of the process — `delegate_notifications`'s cleanup
+/// only fires on the next `tag_changed` notification for that hash, which may
+/// never come.
+struct HashWatchStream<S> {
+    inner: S,
+    hash: Blake2bHash,
+    watchers: Arc<DashMap<Blake2bHash, broadcast::Sender<()>>>,
 }
 
-async fn add_tags_to_hash(
-    txn: &postgres::Transaction<'_>,
-    hash: &Blake2bHash,
-    tags: &[Tag],
-) -> Result<(), Error> {
-    let hash_id = get_or_insert_hash(&txn, &hash).await?;
-    for tag in tags {
-        let tag_id = get_or_insert_tag(&txn, &tag).await?;
-        txn.execute(
-            "INSERT INTO hash_tag(tag_id, hash_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
-            &[&tag_id, &hash_id],
-        )
-        .await?;
+impl<S: Stream + Unpin> Stream for HashWatchStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl<S> Drop for HashWatchStream<S> {
+    fn drop(&mut self) {
+        // `self.inner` (and with it this stream's own receiver) is only
+        // dropped after this runs, so the last subscriber sees a count of 1
+        let last_subscriber_gone = self
+            .watchers
+            .get(&self.hash)
+            .map_or(false, |sender| sender.receiver_count() <= 1);
+        if last_subscriber_gone {
+            self.watchers.remove(&self.hash);
+        }
     }
-    Ok(())
 }
 
 #[tonic::async_trait]
 impl tgcd_server::Tgcd for Tgcd {
-    async fn get_tags(&self, req: Request<Hash>) -> Result<Response<Tags>, Status> {
-        let client = self.inner.pool.get().await.unwrap();
-        let hash = Blake2bHash::try_from(&*req.into_inner().hash).map_err(Error::ArgHash)?;
-        let tags = get_tags(&client, &hash).await?;
+    type WatchTagsStream = Pin<Box<dyn Stream<Item = Result<Tags, Status>> + Send + 'static>>;
 
-        Ok(Response::new(Tags { tags }))
+    async fn watch_tags(
+        &self,
+        req: Request<GetMultipleTagsReq>,
+    ) -> Result<Response<Self::WatchTagsStream>, Status> {
+        metrics::instrument(&self.inner.metrics, "watch_tags", async {
+            let hashes = req
+                .into_inner()
+                .hashes
+                .into_iter()
+                .map(|hash| Blake2bHash::try_from(&*hash))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Error::ArgHash)?;
+
+            let watchers = Arc::clone(&self.inner.watchers);
+
+            let changes = stream::select_all(hashes.into_iter().map(|hash| {
+                let receiver = watchers
+                    .entry(hash)
+                    .or_insert_with(|| broadcast::channel(16).0)
+                    .subscribe();
+                let inner = BroadcastStream::new(receiver).map(move |_| hash);
+                HashWatchStream {
+                    inner,
+                    hash,
+                    watchers: Arc::clone(&watchers),
+                }
+            }));
+
+            let pool = self.inner.pool.clone();
+            let tags = changes.then(move |hash| {
+                let pool = pool.clone();
+                async move {
+                    retry::with_retry(|| {
+                        let pool = pool.clone();
+                        async move {
+                            let client = pool.get().await.map_err(Error::Pool)?;
+                            get_tags(&client, &hash).await.map(|tags| Tags { tags })
+                        }
+                    })
+                    .await
+                }
+            });
+
+            Ok(Response::new(Box::pin(tags)))
+        })
+        .await
     }
 
-    async fn add_tags_to_hash(&self, req: Request<AddTags>) -> Result<Response<()>, Status> {
-        let mut client = self.inner.pool.get().await.unwrap();
-        let AddTags { hash, tags } = req.into_inner();
-        let hash = Blake2bHash::try_from(&*hash).map_err(Error::ArgHash)?;
-        let tags = tags
-            .into_iter()
-            .map(Tag::try_from)
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Error::ArgTag)?;
+    async fn get_tags(&self, req: Request<Hash>) -> Result<Response<Tags>, Status> {
+        metrics::instrument(&self.inner.metrics, "get_tags", async {
+            let hash = Blake2bHash::try_from(&*req.into_inner().hash).map_err(Error::ArgHash)?;
+            let pool = &self.inner.pool;
+
+            let tags = retry::with_retry(|| async {
+                let client = pool.get().await.map_err(Error::Pool)?;
+                get_tags(&client, &hash).await
+            })
+            .await?;
 
-        let txn = client.transaction().map_err(Error::Postgres).await?;
-        add_tags_to_hash(&txn, &hash, &tags).await?;
+            Ok(Response::new(Tags { tags }))
+        })
+        .await
+    }
 
-        txn.commit().map_err(Error::Postgres).await?;
+    async fn add_tags_to_hash(&self, req: Request<AddTags>) -> Result<Response<()>, Status> {
+        metrics::instrument(&self.inner.metrics, "add_tags_to_hash", async {
+            let AddTags { hash, tags } = req.into_inner();
+            let hash = Blake2bHash::try_from(&*hash).map_err(Error::ArgHash)?;
+            let tags = tags
+                .into_iter()
+                .map(Tag::try_from)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Error::ArgTag)?;
+            let pool = &self.inner.pool;
+
+            // the whole transaction is rebuilt on each attempt: a half-applied
+            // one is rolled back by Postgres, so there's nothing to resume.
+            retry::with_retry(|| async {
+                let mut client = pool.get().await.map_err(Error::Pool)?;
+                let txn = client.transaction().map_err(Error::Postgres).await?;
+                add_tags_to_hash(&txn, &hash, &tags).await?;
+                txn.commit().map_err(Error::Postgres).await?;
+                Ok(())
+            })
+            .await?;
 
-        Ok(Response::new(()))
+            Ok(Response::new(()))
+        })
+        .await
     }
 
     async fn get_multiple_tags(
         &self,
         req: Request<GetMultipleTagsReq>,
     ) -> Result<Response<GetMultipleTagsResp>, Status> {
-        let client = self.inner.pool.get().await.unwrap();
-        let hashes = req.into_inner().hashes;
-        let hashes = hashes
-            .into_iter()
-            .map(|hash| Blake2bHash::try_from(&*hash))
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(Error::ArgHash)?;
-
-        let tags = future::try_join_all(
-            hashes
-                .iter()
-                .map(|hash| get_tags(&client, &hash).map_ok(|tags| Tags { tags })),
-        )
-        .await?;
+        metrics::instrument(&self.inner.metrics, "get_multiple_tags", async {
+            let hashes = req.into_inner().hashes;
+            let hashes = hashes
+                .into_iter()
+                .map(|hash| Blake2bHash::try_from(&*hash))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Error::ArgHash)?;
+            let pool = &self.inner.pool;
+
+            let tags = retry::with_retry(|| async {
+                let client = pool.get().await.map_err(Error::Pool)?;
+                future::try_join_all(
+                    hashes
+                        .iter()
+                        .map(|hash| get_tags(&client, &hash).map_ok(|tags| Tags { tags })),
+                )
+                .await
+            })
+            .await?;
 
-        Ok(Response::new(GetMultipleTagsResp { tags }))
+            Ok(Response::new(GetMultipleTagsResp { tags }))
+        })
+        .await
     }
 
     async fn copy_tags(&self, req: Request<SrcDest>) -> Result<Response<()>, Status> {
-        let SrcDest {
-            src_hash,
-            dest_hash,
-        } = req.into_inner();
-        let mut client = self.inner.pool.get().await.unwrap();
-
-        let src_hash = Blake2bHash::try_from(&*src_hash).map_err(Error::ArgHash)?;
-        let dest_hash = Blake2bHash::try_from(&*dest_hash).map_err(Error::ArgHash)?;
-
-        let src_tags = get_tags(&client, &src_hash)
-            .await?
-            .into_iter()
-            .map(|a| Tag::try_from(a).unwrap())
-            .collect::<Vec<_>>();
-
-        let txn = client.transaction().await.map_err(Error::Postgres)?;
-        add_tags_to_hash(&txn, &dest_hash, &src_tags).await?;
-        txn.commit().await.map_err(Error::Postgres)?;
-
-        Ok(Response::new(()))
+        metrics::instrument(&self.inner.metrics, "copy_tags", async {
+            let SrcDest {
+                src_hash,
+                dest_hash,
+            } = req.into_inner();
+
+            let src_hash = Blake2bHash::try_from(&*src_hash).map_err(Error::ArgHash)?;
+            let dest_hash = Blake2bHash::try_from(&*dest_hash).map_err(Error::ArgHash)?;
+            let pool = &self.inner.pool;
+
+            // rebuilt from scratch on each attempt, same reasoning as
+            // `add_tags_to_hash` above.
+            retry::with_retry(|| async {
+                let mut client = pool.get().await.map_err(Error::Pool)?;
+
+                let src_tags = get_tags(&client, &src_hash)
+                    .await?
+                    .into_iter()
+                    .map(|a| Tag::try_from(a).map_err(Error::ArgTag))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let txn = client.transaction().await.map_err(Error::Postgres)?;
+                add_tags_to_hash(&txn, &dest_hash, &src_tags).await?;
+                txn.commit().await.map_err(Error::Postgres)?;
+
+                Ok(())
+            })
+            .await?;
+
+            Ok(Response::new(()))
+        })
+        .await
+    }
+
+    async fn enqueue_task(&self, req: Request<EnqueueTaskReq>) -> Result<Response<TaskId>, Status> {
+        metrics::instrument(&self.inner.metrics, "enqueue_task", async {
+            let EnqueueTaskReq { kind, payload } = req.into_inner();
+            let payload: serde_json::Value = serde_json::from_slice(&payload)
+                .map_err(|_| Status::invalid_argument("payload is not valid JSON"))?;
+
+            let id = task::enqueue(&self.inner.pool, &kind, payload).await?;
+
+            Ok(Response::new(TaskId {
+                task_id: id.as_bytes().to_vec(),
+            }))
+        })
+        .await
+    }
+
+    async fn get_task_status(&self, req: Request<TaskId>) -> Result<Response<TaskStatusResp>, Status> {
+        metrics::instrument(&self.inner.metrics, "get_task_status", async {
+            let task_id = req.into_inner().task_id;
+            let id = uuid::Uuid::from_slice(&task_id)
+                .map_err(|_| Status::invalid_argument("task_id is not a valid uuid"))?;
+
+            let status = task::status(&self.inner.pool, id)
+                .await?
+                .ok_or_else(|| Status::not_found("no such task"))?;
+
+            Ok(Response::new(TaskStatusResp {
+                state: status.state,
+                attempts: status.attempts,
+                error: status.error,
+            }))
+        })
+        .await
     }
 }
 
@@ -294,6 +651,25 @@ async fn run() -> Result<(), SetupError> {
         stream::select_all(signals).next().await;
     };
 
+    let metrics_server =
+        metrics::bind(([0, 0, 0, 0], config.metrics_port).into()).map_err(SetupError::MetricsBind)?;
+    let metrics_handle = Arc::clone(&tgcd.inner.metrics);
+    let metrics_pool = tgcd.inner.pool.clone();
+    tokio::task::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_server, metrics_handle, metrics_pool).await {
+            error!("Metrics server stopped"; slog::o!("error" => e.to_string()));
+        }
+    });
+
+    let task_wake_rx = tgcd
+        .inner
+        .task_wake_rx
+        .lock()
+        .unwrap()
+        .take()
+        .expect("task_wake_rx is only ever taken once, here");
+    tokio::task::spawn(task::run_worker(tgcd.inner.pool.clone(), task_wake_rx));
+
     Server::builder()
         .add_service(tgcd_server::TgcdServer::new(tgcd))
         .serve_with_shutdown(([0, 0, 0, 0], config.port).into(), terminate)
@@ -0,0 +1,144 @@
+//! Prometheus metrics, served on their own port so scraping doesn't have to
+//! go through the tonic/gRPC listener.
+
+use deadpool_postgres::Pool;
+use hyper::{
+    server::{conn::AddrIncoming, Builder},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use prometheus::{Encoder, HistogramVec, IntCounterVec, IntGauge, Registry, TextEncoder};
+use slog_scope::error;
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Instant};
+use tonic::Status;
+
+pub struct Metrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    request_duration_seconds: HistogramVec,
+    pool_size: IntGauge,
+    pool_available: IntGauge,
+    pool_waiting: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        let requests_total = IntCounterVec::new(
+            prometheus::Opts::new("tgcd_requests_total", "Total number of handled RPCs"),
+            &["method", "outcome"],
+        )?;
+        let request_duration_seconds = HistogramVec::new(
+            prometheus::HistogramOpts::new(
+                "tgcd_request_duration_seconds",
+                "RPC handler latency in seconds",
+            ),
+            &["method"],
+        )?;
+        let pool_size = IntGauge::new("tgcd_pool_size", "Current number of connections in the Postgres pool")?;
+        let pool_available = IntGauge::new(
+            "tgcd_pool_available",
+            "Number of idle connections currently available in the pool",
+        )?;
+        let pool_waiting = IntGauge::new(
+            "tgcd_pool_waiting",
+            "Number of callers currently waiting for a pooled connection",
+        )?;
+
+        registry.register(Box::new(requests_total.clone()))?;
+        registry.register(Box::new(request_duration_seconds.clone()))?;
+        registry.register(Box::new(pool_size.clone()))?;
+        registry.register(Box::new(pool_available.clone()))?;
+        registry.register(Box::new(pool_waiting.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests_total,
+            request_duration_seconds,
+            pool_size,
+            pool_available,
+            pool_waiting,
+        })
+    }
+
+    fn update_pool_gauges(&self, pool: &Pool) {
+        let status = pool.status();
+        self.pool_size.set(status.size as i64);
+        self.pool_available.set(status.available as i64);
+        self.pool_waiting.set(status.waiting as i64);
+    }
+}
+
+/// Times `fut` and records it under `method` as a request count (by outcome)
+/// and a latency observation.
+pub async fn instrument<T>(
+    metrics: &Metrics,
+    method: &'static str,
+    fut: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    let start = Instant::now();
+    let result = fut.await;
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics
+        .requests_total
+        .with_label_values(&[method, outcome])
+        .inc();
+    metrics
+        .request_duration_seconds
+        .with_label_values(&[method])
+        .observe(start.elapsed().as_secs_f64());
+
+    result
+}
+
+async fn serve_req(
+    metrics: Arc<Metrics>,
+    pool: Pool,
+    _req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    metrics.update_pool_gauges(&pool);
+
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut buffer) {
+        error!("Failed encoding metrics"; slog::o!("error" => e.to_string()));
+        return Ok(Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    Ok(Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+/// Binds the metrics listener, so a failure (e.g. `addr` already in use) can
+/// be surfaced as a startup error instead of only showing up once the
+/// spawned `serve` future is polled.
+pub fn bind(addr: SocketAddr) -> Result<Builder<AddrIncoming>, hyper::Error> {
+    Server::try_bind(&addr)
+}
+
+/// Serves `/metrics` (and anything else, for simplicity) on the listener
+/// `server` was bound to until the process exits.
+pub async fn serve(
+    server: Builder<AddrIncoming>,
+    metrics: Arc<Metrics>,
+    pool: Pool,
+) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(move |_conn| {
+        let metrics = Arc::clone(&metrics);
+        let pool = pool.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                serve_req(Arc::clone(&metrics), pool.clone(), req)
+            }))
+        }
+    });
+
+    server.serve(make_svc).await
+}
@@ -0,0 +1,87 @@
+use crate::Error;
+use slog_scope::{error, warn};
+use std::time::Duration;
+use tonic::Status;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Classifies a `tokio_postgres::Error` as transient (worth retrying on a
+/// fresh connection) or fatal (retrying would just reproduce the same
+/// failure).
+pub fn is_retryable(err: &tokio_postgres::Error) -> bool {
+    use tokio_postgres::error::SqlState;
+
+    match err.code() {
+        Some(&SqlState::ADMIN_SHUTDOWN) | Some(&SqlState::TOO_MANY_CONNECTIONS) => true,
+        // a missing SQLSTATE means the server never got to respond at all,
+        // i.e. this is a transport/connection failure rather than a
+        // rejected query
+        None => true,
+        Some(_) => false,
+    }
+}
+
+fn is_retryable_pool_error(err: &deadpool_postgres::PoolError) -> bool {
+    match err {
+        deadpool_postgres::PoolError::Backend(e) => is_retryable(e),
+        deadpool_postgres::PoolError::Timeout(_) => true,
+        _ => false,
+    }
+}
+
+impl Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Error::Postgres(e) => is_retryable(e),
+            Error::Pool(e) => is_retryable_pool_error(e),
+            Error::ArgHash(_) | Error::ArgTag(_) | Error::InvalidTaskPayload(_) => false,
+        }
+    }
+}
+
+/// Runs `op` up to `MAX_ATTEMPTS` times in all, with doubling backoff, as long as
+/// the error it returns is classified as transient. `op` is expected to
+/// rebuild its work from scratch on each call (acquire a fresh pooled
+/// connection, start a new transaction, ...): a half-applied transaction is
+/// rolled back by Postgres, so there's nothing to resume.
+pub async fn with_retry<T, F, Fut>(mut op: F) -> Result<T, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Error>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < MAX_ATTEMPTS && e.is_retryable() => {
+                warn!(
+                    "Retrying after transient db error";
+                    slog::o!("attempt" => attempt, "error" => e.to_string())
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+            Err(e) if e.is_retryable() => {
+                error!("Exhausted db retries"; slog::o!("error" => e.to_string()));
+                return Err(Status::unavailable("db error"));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    unreachable!("loop above always returns before the attempt counter is exhausted")
+}
+
+/// Fetches a pooled connection, retrying transient pool/connection errors.
+pub async fn get_client(
+    pool: &deadpool_postgres::Pool,
+) -> Result<deadpool_postgres::Client, Status> {
+    with_retry(|| {
+        let pool = pool.clone();
+        async move { pool.get().await.map_err(Error::Pool) }
+    })
+    .await
+}
@@ -0,0 +1,385 @@
+//! Durable background-job queue for bulk/deferred tag operations.
+//!
+//! Jobs are rows in the `task` table. `enqueue`/`status` let RPC handlers
+//! submit work and poll its outcome; [`run_worker`] is the consumer side,
+//! claiming one row at a time with `FOR UPDATE SKIP LOCKED` so multiple
+//! instances of this server can share one queue without double-processing a
+//! task.
+
+use crate::{add_tags_to_hash, get_tags, Error};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use slog_scope::{error, warn};
+use std::{convert::TryFrom, time::Duration};
+use tgcd::{Blake2bHash, Tag};
+use tokio_postgres as postgres;
+use uuid::Uuid;
+
+const MAX_ATTEMPTS: i32 = 5;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+enum TaskError {
+    #[error("Error from postgres: {0}")]
+    Postgres(#[from] postgres::Error),
+
+    #[error("Error from connection pool: {0}")]
+    Pool(#[from] deadpool_postgres::PoolError),
+
+    #[error("Invalid task payload: {0}")]
+    Payload(String),
+
+    #[error("Invalid hash in task payload: {0}")]
+    Hash(String),
+
+    #[error("Invalid tag in task payload: {0}")]
+    Tag(String),
+}
+
+impl From<Error> for TaskError {
+    fn from(other: Error) -> Self {
+        match other {
+            Error::Postgres(e) => TaskError::Postgres(e),
+            Error::Pool(e) => TaskError::Pool(e),
+            Error::ArgHash(e) => TaskError::Hash(e.to_string()),
+            Error::ArgTag(e) => TaskError::Tag(e.to_string()),
+            Error::InvalidTaskPayload(e) => TaskError::Payload(e),
+        }
+    }
+}
+
+impl TaskError {
+    /// Whether this is caused by bad input data rather than a transient db
+    /// hiccup. Retrying a permanent failure with backoff would just
+    /// reproduce the same error, so it should go straight to `failed`
+    /// instead of burning through `MAX_ATTEMPTS`.
+    fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            TaskError::Payload(_) | TaskError::Hash(_) | TaskError::Tag(_)
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Payload {
+    /// Copy the tags of every hash tagged `tag` onto `dest_hash`.
+    CopyTagsMatching { tag: String, dest_hash: String },
+    /// Tag each `hash` with `tags`, in one task instead of one RPC per pair.
+    BulkImport { entries: Vec<BulkImportEntry> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BulkImportEntry {
+    hash: String,
+    tags: Vec<String>,
+}
+
+pub struct TaskStatus {
+    pub state: String,
+    pub attempts: i32,
+    pub error: String,
+}
+
+fn decode_hash(hex_hash: &str) -> Result<Blake2bHash, TaskError> {
+    let bytes = hex::decode(hex_hash).map_err(|e| TaskError::Hash(e.to_string()))?;
+    Blake2bHash::try_from(&*bytes).map_err(|e| TaskError::Hash(e.to_string()))
+}
+
+/// Checks that every hash/tag embedded in `payload` actually decodes, the
+/// same way every other RPC in `main.rs` validates its arguments
+/// synchronously. Without this, a malformed `BulkImport` entry or
+/// `CopyTagsMatching::dest_hash` would only be discovered once the worker
+/// claimed the task, and `TaskError::is_permanent` aside, there would be no
+/// better outcome than a slow trip through `MAX_ATTEMPTS` retries.
+fn validate_payload(payload: &Payload) -> Result<(), TaskError> {
+    match payload {
+        Payload::CopyTagsMatching { dest_hash, .. } => {
+            decode_hash(dest_hash)?;
+        }
+        Payload::BulkImport { entries } => {
+            for entry in entries {
+                decode_hash(&entry.hash)?;
+                for tag in &entry.tags {
+                    Tag::try_from(tag.clone()).map_err(|e| TaskError::Tag(e.to_string()))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Enqueues a task and returns its id.
+///
+/// `kind` drives the `task.kind` column used to match this codebase's
+/// `Payload` variants; rather than trusting the caller to also set a
+/// matching `"kind"` tag inside `payload`'s JSON (and risk the two
+/// diverging), this stamps `kind` into the payload itself and rejects
+/// anything that doesn't then deserialize as a known `Payload` variant, so
+/// a bad request fails the RPC instead of failing (and retrying) in the
+/// worker. The hashes/tags inside it are validated the same way, so a
+/// malformed one is rejected here too instead of surfacing only once the
+/// worker claims the task.
+pub async fn enqueue(pool: &Pool, kind: &str, mut payload: serde_json::Value) -> Result<Uuid, Error> {
+    let obj = payload
+        .as_object_mut()
+        .ok_or_else(|| Error::InvalidTaskPayload("payload must be a JSON object".to_owned()))?;
+    obj.insert(
+        "kind".to_owned(),
+        serde_json::Value::String(kind.to_owned()),
+    );
+
+    let parsed = serde_json::from_value::<Payload>(payload.clone())
+        .map_err(|e| Error::InvalidTaskPayload(e.to_string()))?;
+    validate_payload(&parsed).map_err(|e| Error::InvalidTaskPayload(e.to_string()))?;
+
+    let client = pool.get().await.map_err(Error::Pool)?;
+    let stmnt = client
+        .prepare("INSERT INTO task(kind, payload) VALUES ($1, $2) RETURNING id")
+        .await
+        .map_err(Error::Postgres)?;
+    let row = client
+        .query_one(&stmnt, &[&kind, &payload])
+        .await
+        .map_err(Error::Postgres)?;
+
+    Ok(row.get(0))
+}
+
+pub async fn status(pool: &Pool, id: Uuid) -> Result<Option<TaskStatus>, Error> {
+    let client = pool.get().await.map_err(Error::Pool)?;
+    let row = client
+        .query_opt(
+            "SELECT state, attempts, error FROM task WHERE id = $1",
+            &[&id],
+        )
+        .await
+        .map_err(Error::Postgres)?;
+
+    Ok(row.map(|row| TaskStatus {
+        state: row.get(0),
+        attempts: row.get(1),
+        error: row.get::<_, Option<String>>(2).unwrap_or_default(),
+    }))
+}
+
+struct ClaimedTask {
+    id: Uuid,
+    kind: String,
+    payload: serde_json::Value,
+    attempts: i32,
+}
+
+/// Claims the oldest due `new` task, if any, marking it `in_progress` so no
+/// other worker picks it up. `failed` is a terminal state (reached after
+/// `MAX_ATTEMPTS`, or on the first permanent error) and is deliberately
+/// excluded here — a task being
+/// retried stays in `new` (see `fail_task`), so picking up `failed` rows too
+/// would run exhausted tasks forever.
+async fn claim_task(client: &mut postgres::Client) -> Result<Option<ClaimedTask>, TaskError> {
+    let txn = client.transaction().await?;
+
+    let row = txn
+        .query_opt(
+            "SELECT id, kind, payload, attempts FROM task
+             WHERE state = 'new' AND scheduled_at <= now()
+             ORDER BY scheduled_at
+             FOR UPDATE SKIP LOCKED
+             LIMIT 1",
+            &[],
+        )
+        .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+    };
+
+    let claimed = ClaimedTask {
+        id: row.get(0),
+        kind: row.get(1),
+        payload: row.get(2),
+        attempts: row.get(3),
+    };
+
+    txn.execute(
+        "UPDATE task SET state = 'in_progress' WHERE id = $1",
+        &[&claimed.id],
+    )
+    .await?;
+    txn.commit().await?;
+
+    Ok(Some(claimed))
+}
+
+async fn finish_task(client: &postgres::Client, id: Uuid) -> Result<(), TaskError> {
+    client
+        .execute("UPDATE task SET state = 'finished' WHERE id = $1", &[&id])
+        .await?;
+    Ok(())
+}
+
+/// Marks a failed task `failed` once `MAX_ATTEMPTS` is reached or the error
+/// is `permanent` (bad input data, where retrying would just reproduce the
+/// same failure), otherwise reschedules it with doubling backoff.
+async fn fail_task(
+    client: &postgres::Client,
+    id: Uuid,
+    attempts: i32,
+    error: &str,
+    permanent: bool,
+) -> Result<(), TaskError> {
+    if permanent || attempts + 1 >= MAX_ATTEMPTS {
+        client
+            .execute(
+                "UPDATE task SET state = 'failed', attempts = attempts + 1, error = $2 WHERE id = $1",
+                &[&id, &error],
+            )
+            .await?;
+    } else {
+        let backoff_secs = 2i32.pow(attempts as u32);
+        client
+            .execute(
+                "UPDATE task
+                 SET state = 'new', attempts = attempts + 1, error = $2,
+                     scheduled_at = now() + ($3 || ' seconds')::interval
+                 WHERE id = $1",
+                &[&id, &error, &backoff_secs.to_string()],
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn run_copy_tags_matching(
+    client: &mut postgres::Client,
+    tag: &str,
+    dest_hash: Blake2bHash,
+) -> Result<(), TaskError> {
+    let stmnt = client
+        .prepare(
+            "
+    SELECT DISTINCT hash.hash
+    FROM hash
+    JOIN hash_tag ON hash_tag.hash_id = hash.id
+    JOIN tag ON tag.id = hash_tag.tag_id
+    WHERE tag.name = $1",
+        )
+        .await?;
+    let rows = client.query(&stmnt, &[&tag]).await?;
+
+    let mut tags = std::collections::BTreeSet::new();
+    for row in &rows {
+        let hash_bytes: Vec<u8> = row.get(0);
+        let hash = Blake2bHash::try_from(&*hash_bytes).map_err(|e| TaskError::Hash(e.to_string()))?;
+        tags.extend(get_tags(client, &hash).await?);
+    }
+
+    let tags = tags
+        .into_iter()
+        .map(Tag::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TaskError::Tag(e.to_string()))?;
+
+    let txn = client.transaction().await?;
+    add_tags_to_hash(&txn, &dest_hash, &tags).await?;
+    txn.commit().await?;
+
+    Ok(())
+}
+
+async fn run_bulk_import(
+    client: &mut postgres::Client,
+    entries: Vec<BulkImportEntry>,
+) -> Result<(), TaskError> {
+    for entry in entries {
+        let hash = decode_hash(&entry.hash)?;
+        let tags = entry
+            .tags
+            .into_iter()
+            .map(Tag::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| TaskError::Tag(e.to_string()))?;
+
+        let txn = client.transaction().await?;
+        add_tags_to_hash(&txn, &hash, &tags).await?;
+        txn.commit().await?;
+    }
+
+    Ok(())
+}
+
+async fn run_task(client: &mut postgres::Client, claimed: &ClaimedTask) -> Result<(), TaskError> {
+    let payload: Payload = serde_json::from_value(claimed.payload.clone())
+        .map_err(|e| TaskError::Payload(e.to_string()))?;
+
+    match payload {
+        Payload::CopyTagsMatching { tag, dest_hash } => {
+            run_copy_tags_matching(client, &tag, decode_hash(&dest_hash)?).await
+        }
+        Payload::BulkImport { entries } => run_bulk_import(client, entries).await,
+    }
+}
+
+async fn claim_and_run_one(pool: &Pool) -> Result<bool, TaskError> {
+    let mut client = pool.get().await.map_err(TaskError::Pool)?;
+
+    let claimed = match claim_task(&mut client).await? {
+        Some(claimed) => claimed,
+        None => return Ok(false),
+    };
+
+    match run_task(&mut client, &claimed).await {
+        Ok(()) => finish_task(&client, claimed.id).await?,
+        Err(e) => {
+            warn!(
+                "Task failed";
+                slog::o!(
+                    "task_id" => claimed.id.to_string(),
+                    "kind" => claimed.kind.clone(),
+                    "attempt" => claimed.attempts,
+                    "error" => e.to_string()
+                )
+            );
+            fail_task(
+                &client,
+                claimed.id,
+                claimed.attempts,
+                &e.to_string(),
+                e.is_permanent(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Drains due tasks one at a time until the queue is empty, then waits for
+/// either a `task_queue` notification or `POLL_INTERVAL` to elapse before
+/// looking again. The poll fallback covers notifications missed while the
+/// worker itself was down or mid-task.
+pub async fn run_worker(pool: Pool, mut wake: tokio::sync::mpsc::UnboundedReceiver<()>) {
+    loop {
+        loop {
+            match claim_and_run_one(&pool).await {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    error!("Task worker iteration failed"; slog::o!("error" => e.to_string()));
+                    break;
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = wake.recv() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
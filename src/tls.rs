@@ -0,0 +1,223 @@
+//! TLS support for the Postgres connection, layered on top of
+//! `tokio-postgres-rustls`. Kept generic over [`MakeTlsConnect`] so the same
+//! connector can be handed to the one-off migration connection, the
+//! notification-listener connection and the deadpool [`Manager`].
+
+use serde::Deserialize;
+use std::{
+    fs,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::{
+    config::SslMode,
+    tls::{ChannelBinding, MakeTlsConnect, TlsConnect, TlsStream},
+};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Never negotiate TLS. The default, so existing deployments are
+    /// unaffected.
+    Disable,
+    /// Use TLS if the server offers it, fall back to plaintext otherwise.
+    Prefer,
+    /// Refuse to connect unless TLS is negotiated.
+    Require,
+}
+
+impl Default for TlsMode {
+    fn default() -> Self {
+        TlsMode::Disable
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub pg_tls_mode: TlsMode,
+    pub pg_tls_ca_cert_path: Option<PathBuf>,
+    pub pg_tls_client_cert_path: Option<PathBuf>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsSetupError {
+    #[error("Can't read TLS certificate at {path}: {source}")]
+    ReadCert {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Invalid TLS certificate: {0}")]
+    InvalidCert(#[source] rustls::TLSError),
+}
+
+/// Either a plaintext connection or a `rustls`-backed one, so the rest of
+/// the code doesn't need to be generic over the TLS backend.
+#[derive(Clone)]
+pub enum Connector {
+    NoTls(tokio_postgres::NoTls),
+    Rustls(MakeRustlsConnect),
+}
+
+/// Tells `tokio_postgres` itself whether to fall back to plaintext
+/// (`Prefer`) or refuse the connection (`Require`) when the server doesn't
+/// negotiate TLS. `Connector` alone can't enforce this — it's only ever
+/// asked to connect *with* TLS, so without this the two modes behave
+/// identically.
+pub fn apply_ssl_mode(cfg: &TlsConfig, postgres_config: &mut tokio_postgres::Config) {
+    postgres_config.ssl_mode(match cfg.pg_tls_mode {
+        TlsMode::Disable => SslMode::Disable,
+        TlsMode::Prefer => SslMode::Prefer,
+        TlsMode::Require => SslMode::Require,
+    });
+}
+
+impl Connector {
+    pub fn new(cfg: &TlsConfig) -> Result<Self, TlsSetupError> {
+        if cfg.pg_tls_mode == TlsMode::Disable {
+            return Ok(Connector::NoTls(tokio_postgres::NoTls));
+        }
+
+        let mut roots = rustls::RootCertStore::empty();
+        if let Some(ca_path) = &cfg.pg_tls_ca_cert_path {
+            let pem = fs::read(ca_path).map_err(|source| TlsSetupError::ReadCert {
+                path: ca_path.clone(),
+                source,
+            })?;
+            roots
+                .add_pem_file(&mut io::Cursor::new(pem))
+                .map_err(|()| TlsSetupError::InvalidCert(rustls::TLSError::NoCertificatesPresented))?;
+        } else {
+            roots.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        }
+
+        let mut client_config = rustls::ClientConfig::new();
+        client_config.root_store = roots;
+
+        if let Some(client_cert_path) = &cfg.pg_tls_client_cert_path {
+            let pem = fs::read(client_cert_path).map_err(|source| TlsSetupError::ReadCert {
+                path: client_cert_path.clone(),
+                source,
+            })?;
+            let certs = rustls::internal::pemfile::certs(&mut io::Cursor::new(&pem))
+                .map_err(|()| TlsSetupError::InvalidCert(rustls::TLSError::NoCertificatesPresented))?;
+            let key = rustls::internal::pemfile::pkcs8_private_keys(&mut io::Cursor::new(&pem))
+                .ok()
+                .and_then(|mut keys| keys.pop())
+                .ok_or(TlsSetupError::InvalidCert(rustls::TLSError::NoCertificatesPresented))?;
+            client_config
+                .set_single_client_cert(certs, key)
+                .map_err(TlsSetupError::InvalidCert)?;
+        }
+
+        Ok(Connector::Rustls(MakeRustlsConnect::new(client_config)))
+    }
+}
+
+impl<S> MakeTlsConnect<S> for Connector
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = EitherStream<
+        <tokio_postgres::NoTls as MakeTlsConnect<S>>::Stream,
+        <MakeRustlsConnect as MakeTlsConnect<S>>::Stream,
+    >;
+    type TlsConnect = EitherTlsConnect<S>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn make_tls_connect(&mut self, hostname: &str) -> Result<Self::TlsConnect, Self::Error> {
+        Ok(match self {
+            Connector::NoTls(c) => EitherTlsConnect::NoTls(c.make_tls_connect(hostname)?),
+            Connector::Rustls(c) => EitherTlsConnect::Rustls(c.make_tls_connect(hostname)?),
+        })
+    }
+}
+
+pub enum EitherTlsConnect<S> {
+    NoTls(<tokio_postgres::NoTls as MakeTlsConnect<S>>::TlsConnect),
+    Rustls(<MakeRustlsConnect as MakeTlsConnect<S>>::TlsConnect),
+}
+
+impl<S> TlsConnect<S> for EitherTlsConnect<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = EitherStream<
+        <tokio_postgres::NoTls as MakeTlsConnect<S>>::Stream,
+        <MakeRustlsConnect as MakeTlsConnect<S>>::Stream,
+    >;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future =
+        std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        Box::pin(async move {
+            match self {
+                EitherTlsConnect::NoTls(c) => Ok(EitherStream::Plain(c.connect(stream).await?)),
+                EitherTlsConnect::Rustls(c) => Ok(EitherStream::Tls(c.connect(stream).await?)),
+            }
+        })
+    }
+}
+
+/// Unifies the plaintext and TLS stream types behind one concrete type so
+/// `Connector` can implement `MakeTlsConnect` without boxing every read/write.
+pub enum EitherStream<A, B> {
+    Plain(A),
+    Tls(B),
+}
+
+impl<A: AsyncRead + Unpin, B: AsyncRead + Unpin> AsyncRead for EitherStream<A, B> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            EitherStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            EitherStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<A: AsyncWrite + Unpin, B: AsyncWrite + Unpin> AsyncWrite for EitherStream<A, B> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            EitherStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            EitherStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            EitherStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            EitherStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            EitherStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            EitherStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+impl<A: TlsStream + Unpin, B: TlsStream + Unpin> TlsStream for EitherStream<A, B> {
+    fn channel_binding(&self) -> ChannelBinding {
+        match self {
+            EitherStream::Plain(s) => s.channel_binding(),
+            EitherStream::Tls(s) => s.channel_binding(),
+        }
+    }
+}